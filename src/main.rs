@@ -1,9 +1,40 @@
 extern crate getopts;
 extern crate time;
+extern crate ctrlc;
+extern crate libc;
+extern crate toml;
 
 use getopts::{Options, Matches};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::error::Error;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use std::ffi::{OsStr, OsString};
+
+/// Opens (creating if necessary) a relaunch log file for appending.
+fn open_logfile(path: &std::path::Path) -> std::io::Result<std::fs::File> {
+    std::fs::OpenOptions::new().create(true).append(true).open(path)
+}
+
+/// How often blocking waits re-check `shutdown`, used both by `wait_for_child`
+/// and `interruptible_sleep`.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Sleeps for `duration`, polling `shutdown` every `POLL_INTERVAL` so a
+/// shutdown request is noticed well before the full duration elapses.
+/// Returns `true` if interrupted by a shutdown request, `false` if the full
+/// duration elapsed.
+fn interruptible_sleep(duration: Duration, shutdown: &Arc<AtomicBool>) -> bool {
+    let start = std::time::Instant::now();
+    while start.elapsed() < duration {
+        if shutdown.load(Ordering::SeqCst) {
+            return true;
+        }
+        std::thread::sleep(POLL_INTERVAL.min(duration.saturating_sub(start.elapsed())));
+    }
+    false
+}
 
 fn print_usage(opts: Options) {
     let brief = format!("{} TARGET [-- TARGET_OPTIONS]", opts.short_usage("relaunch"));
@@ -16,32 +47,94 @@ fn print_version() {
     println!("Licensed under the MIT open source license.");
 }
 
-fn main() {
+/// Sentinel embedded in the placeholder substituted for a non-UTF-8 pre-`--`
+/// arg (see `split_args`). Wrapped in NUL bytes, which can never appear in a
+/// real argv entry, so it can't collide with (and be mistaken for) another,
+/// possibly also-lossy, arg.
+const NON_UTF8_MARKER: &str = "\0relaunch-non-utf8-arg:";
+
+/// Passthru args (after `--`) are kept as raw OsString and can be non-UTF-8.
+/// Pre-`--` args are parsed by getopts, which requires valid UTF-8 strings to
+/// match against; we feed it a placeholder for any non-UTF-8 arg so parsing
+/// can proceed, but keep the original OsString alongside it in `raw_args`
+/// (same indices) and its index in the returned set. `recover_target_idx`
+/// later uses that set to recover a non-UTF-8 TARGET from `raw_args` while
+/// still rejecting a non-UTF-8 option name or value.
+///
+/// Returns `(args, raw_args, non_utf8_indices, passthru_args)`.
+fn split_args(args_os: impl Iterator<Item = OsString>) -> (Vec<String>, Vec<OsString>, std::collections::HashSet<usize>, Vec<OsString>) {
     let mut args = Vec::<String>::new();
-    let mut passthru_args = Vec::<String>::new();
+    let mut raw_args = Vec::<OsString>::new();
+    let mut non_utf8_indices = std::collections::HashSet::new();
+    let mut passthru_args = Vec::<OsString>::new();
 
     let mut separator_found = false;
-    for arg in std::env::args().skip(1) {
-        if arg == "--" {
+    for arg in args_os {
+        if !separator_found && arg == OsStr::new("--") {
             separator_found = true;
             continue;
         }
         if !separator_found {
-            args.push(arg);
+            raw_args.push(arg.clone());
+            match arg.into_string() {
+                Ok(s) => args.push(s),
+                Err(_) => {
+                    non_utf8_indices.insert(args.len());
+                    args.push(format!("{}{}\0", NON_UTF8_MARKER, args.len()));
+                }
+            }
         }
         else {
             passthru_args.push(arg);
         }
     }
 
+    (args, raw_args, non_utf8_indices, passthru_args)
+}
+
+/// The only pre-`--` arg allowed to be non-UTF-8 is the free/positional
+/// TARGET; anything else that needed a placeholder was an option name or
+/// value, which getopts must have parsed correctly. Returns the recovered
+/// index of TARGET within `raw_args`/`args` (`None` if TARGET was valid
+/// UTF-8 to begin with), or an `Err` if some other arg needed a placeholder.
+fn recover_target_idx(free: &[String], non_utf8_indices: &std::collections::HashSet<usize>) -> Result<Option<usize>, String> {
+    let target_idx = free.first().and_then(|s| {
+        s.strip_prefix(NON_UTF8_MARKER).and_then(|rest| rest.strip_suffix('\0')).and_then(|idx| idx.parse::<usize>().ok())
+    });
+    if non_utf8_indices.iter().any(|idx| Some(*idx) != target_idx) {
+        return Err("relaunch's own options must be valid UTF-8 (TARGET may be non-UTF-8; place other non-UTF-8 values after --)!".to_owned());
+    }
+    Ok(target_idx)
+}
+
+fn main() {
+    let (args, raw_args, non_utf8_indices, passthru_args) = split_args(std::env::args_os().skip(1));
+
     let mut opts = Options::new();
     opts.optflag("a", "always-restart", "Always restart target, even on clean exit");
-    // opts.optopt("j", "instances", "The number of instances of target to run in parallel", "N");
+    opts.optopt("j", "instances", "The number of instances of target to run in parallel", "N");
     opts.optopt("m", "max-restarts", "The maximum number of times to restart a process", "N");
     opts.optopt("i", "restart-interval", "Reset restart counter after SECS seconds", "SECS");
     opts.optopt("o", "stdout", "Redirect target stdout to PATH", "PATH");
     opts.optopt("e", "stderr", "Redirect target stderr to PATH", "PATH");
     opts.optopt("l", "log", "Path to relaunch output log", "PATH");
+    opts.optopt("", "stop-signal", "Signal to forward to the child on shutdown (default SIGTERM)", "SIGNAL");
+    opts.optopt("", "stop-timeout", "Seconds to wait after --stop-signal before sending SIGKILL", "SECS");
+    opts.optopt("", "backoff-base", "Initial delay in seconds before relaunching a fast-crashing target (default 1)", "SECS");
+    opts.optopt("", "backoff-cap", "Maximum delay in seconds between relaunches of a fast-crashing target (default 60)", "SECS");
+    opts.optflag("", "jitter", "Add up to ±10% random jitter to backoff delays");
+    opts.optopt("", "memory-limit", "Limit the target's address space to BYTES (RLIMIT_AS)", "BYTES");
+    opts.optopt("", "cpu-limit", "Limit the target's CPU time to SECS seconds (RLIMIT_CPU)", "SECS");
+    opts.optopt("", "max-fds", "Limit the target's open file descriptors to N (RLIMIT_NOFILE)", "N");
+    opts.optopt("", "core-limit", "Limit the target's core dump size to BYTES (RLIMIT_CORE)", "BYTES");
+    opts.optopt("", "chroot", "Chroot the target into PATH before exec", "PATH");
+    opts.optopt("", "chdir", "Change the target's working directory to PATH before exec", "PATH");
+    opts.optopt("", "user", "Drop privileges to this user (name or uid) before exec", "NAME_OR_UID");
+    opts.optopt("", "group", "Drop privileges to this group (name or gid) before exec", "NAME_OR_GID");
+    opts.optopt("", "restart-codes", "Only restart when the exit code is in this comma/range list (e.g. 1,2,69-75)", "LIST");
+    opts.optopt("", "no-restart-codes", "Never restart when the exit code is in this comma/range list", "LIST");
+    opts.optflag("", "no-restart-on-signal", "Do not restart when the target is terminated by a signal");
+    opts.optopt("", "config", "Supervise the services defined in this TOML file instead of a single TARGET", "FILE");
     opts.optflag("h", "help", "Print this help message and exit");
     opts.optflag("V", "version", "Print version info and exit");
 
@@ -54,6 +147,14 @@ fn main() {
         }
     };
 
+    let target_idx = match recover_target_idx(&matches.free, &non_utf8_indices) {
+        Ok(idx) => idx,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
+        }
+    };
+
     if matches.opt_present("h") {
         print_usage(opts);
         return;
@@ -64,11 +165,10 @@ fn main() {
     }
 
     let mut moptions = MonitorOptions::new();
-    // let mut loptions = LaunchOptions::new();
 
-    // if matches.opt_present("j") {
-    //     moptions.instances = unwrap_argument(&matches, "j", "-j/--instances requires a numeric value!");
-    // }
+    if matches.opt_present("j") {
+        moptions.instances = unwrap_argument(&matches, "j", "-j/--instances requires a numeric value!");
+    }
     if matches.opt_present("m") {
         moptions.max_restarts = Some(unwrap_argument(&matches, "m", "-m/--max-restarts requires a numeric value!"));
     }
@@ -87,13 +187,108 @@ fn main() {
     if matches.opt_present("a") {
         moptions.restart_always = true;
     }
+    if matches.opt_present("stop-signal") {
+        let raw = matches.opt_str("stop-signal").unwrap();
+        moptions.stop_signal = match parse_signal(&raw) {
+            Some(sig) => sig,
+            None => {
+                eprintln!("Error: --stop-signal must be a signal name (e.g. SIGTERM) or number!");
+                std::process::exit(1);
+            }
+        };
+    }
+    if matches.opt_present("stop-timeout") {
+        moptions.stop_timeout = unwrap_argument(&matches, "stop-timeout", "--stop-timeout requires a numeric value!");
+    }
+    if matches.opt_present("backoff-base") {
+        moptions.backoff_base = unwrap_argument(&matches, "backoff-base", "--backoff-base requires a numeric value!");
+    }
+    if matches.opt_present("backoff-cap") {
+        moptions.backoff_cap = unwrap_argument(&matches, "backoff-cap", "--backoff-cap requires a numeric value!");
+    }
+    if matches.opt_present("jitter") {
+        moptions.jitter = true;
+    }
+    if matches.opt_present("memory-limit") {
+        moptions.memory_limit = Some(unwrap_argument(&matches, "memory-limit", "--memory-limit requires a numeric value!"));
+    }
+    if matches.opt_present("cpu-limit") {
+        moptions.cpu_limit = Some(unwrap_argument(&matches, "cpu-limit", "--cpu-limit requires a numeric value!"));
+    }
+    if matches.opt_present("max-fds") {
+        moptions.max_fds = Some(unwrap_argument(&matches, "max-fds", "--max-fds requires a numeric value!"));
+    }
+    if matches.opt_present("core-limit") {
+        moptions.core_limit = Some(unwrap_argument(&matches, "core-limit", "--core-limit requires a numeric value!"));
+    }
+    if matches.opt_present("chroot") {
+        moptions.chroot = Some(unwrap_argument2(&matches, "chroot"));
+    }
+    if matches.opt_present("chdir") {
+        moptions.chdir = Some(unwrap_argument2(&matches, "chdir"));
+    }
+    if matches.opt_present("user") {
+        let raw = matches.opt_str("user").unwrap();
+        moptions.uid = Some(match resolve_user(&raw) {
+            Ok(uid) => uid,
+            Err(e) => {
+                eprintln!("Error: could not resolve --user '{}': {}", raw, e);
+                std::process::exit(1);
+            }
+        });
+    }
+    if matches.opt_present("group") {
+        let raw = matches.opt_str("group").unwrap();
+        moptions.gid = Some(match resolve_group(&raw) {
+            Ok(gid) => gid,
+            Err(e) => {
+                eprintln!("Error: could not resolve --group '{}': {}", raw, e);
+                std::process::exit(1);
+            }
+        });
+    }
+    if matches.opt_present("restart-codes") {
+        let raw = matches.opt_str("restart-codes").unwrap();
+        moptions.restart_codes = Some(match parse_code_list(&raw) {
+            Ok(codes) => codes,
+            Err(e) => {
+                eprintln!("Error: invalid --restart-codes list '{}': {}", raw, e);
+                std::process::exit(1);
+            }
+        });
+    }
+    if matches.opt_present("no-restart-codes") {
+        let raw = matches.opt_str("no-restart-codes").unwrap();
+        moptions.no_restart_codes = Some(match parse_code_list(&raw) {
+            Ok(codes) => codes,
+            Err(e) => {
+                eprintln!("Error: invalid --no-restart-codes list '{}': {}", raw, e);
+                std::process::exit(1);
+            }
+        });
+    }
+    if matches.opt_present("no-restart-on-signal") {
+        moptions.restart_on_signal = false;
+    }
+    if matches.opt_present("config") {
+        moptions.config = Some(unwrap_argument2(&matches, "config"));
+    }
 
-    if matches.free.len() != 1 {
+    if moptions.config.is_some() {
+        if !matches.free.is_empty() {
+            eprintln!("Error: TARGET must not be given together with --config!");
+            std::process::exit(1);
+        }
+    }
+    else if matches.free.len() != 1 {
         eprintln!("Error: TARGET must be specified and cannot include more than one command!");
         std::process::exit(1);
     }
 
-    let target = &matches.free[0];
+    let target = match target_idx {
+        Some(idx) => raw_args[idx].clone(),
+        None => matches.free.first().map(|s| OsString::from(s.clone())).unwrap_or_default(),
+    };
 
     let loptions = LaunchOptions {
         exe: target,
@@ -104,7 +299,7 @@ fn main() {
 
     //initialize logger
     if let Some(ref p) = moptions.log {
-        logfile = match std::fs::OpenOptions::new().create(true).append(true).open(&p) {
+        logfile = match open_logfile(p) {
             Err(e) => {
                 eprintln!("Could not create log file: {}", e.description());
                 std::process::exit(-1);
@@ -113,49 +308,400 @@ fn main() {
         };
     };
 
-    let logger = |s: &str| {
-        println!("{}", s);
+    let has_log = moptions.log.is_some();
+    let logfile = Arc::new(std::sync::Mutex::new(logfile));
+
+    let shutdown = Arc::new(AtomicBool::new(false));
+    let shutdown_handler = shutdown.clone();
+    if let Err(e) = ctrlc::set_handler(move || {
+        shutdown_handler.store(true, Ordering::SeqCst);
+    }) {
+        eprintln!("Warning: failed to install signal handler: {}", e);
+    }
+
+    let exit_code = if let Some(ref config_path) = moptions.config {
+        let default_log = moptions.log.clone();
+        let services = match load_config(config_path, &moptions) {
+            Ok(s) => s,
+            Err(e) => {
+                eprintln!("Error reading --config file: {}", e);
+                std::process::exit(1);
+            }
+        };
+        let results = run_services(services, &shutdown, &default_log, &logfile, has_log);
+        summarize_services(&results, make_logger(&logfile, has_log, None))
+    }
+    else if moptions.instances > 1 {
+        let results = run_instances(&loptions, &moptions, &shutdown, &logfile, has_log, None);
+        summarize_instances(&results, make_logger(&logfile, has_log, None))
+    }
+    else {
+        let logger = make_logger(&logfile, has_log, None);
+        match relaunch(&loptions, &moptions, logger, &shutdown, 0) {
+            Ok(ref result) => result_exit_code(result),
+            Err(err) => {
+                println!("{}", describe_relaunch_error(&err));
+                -1
+            }
+        }
+    };
+
+    std::process::exit(exit_code);
+}
 
-        if moptions.log.is_some() {
+/// Builds a logger closure that prints to stdout and, if logging is
+/// enabled, appends an RFC3339-stamped line to the shared log file. Lines
+/// are tagged with `tag` (if given) so multi-instance or multi-service runs
+/// can be told apart in a single combined log.
+fn make_logger(logfile: &Arc<std::sync::Mutex<Option<std::fs::File>>>, has_log: bool, tag: Option<String>) -> impl FnMut(&str) {
+    let logfile = logfile.clone();
+    move |s: &str| {
+        let line = match tag {
+            Some(ref tag) => format!("[{}] {}", tag, s),
+            None => s.to_owned(),
+        };
+        println!("{}", line);
+
+        if has_log {
             use std::io::prelude::*;
 
             let prefix = format!("{} - ", time::now_utc().rfc3339());
             let mut bytes: Vec<u8> = prefix.bytes().collect();
 
-            for b in s.bytes() {
+            for b in line.bytes() {
                 bytes.push(b);
             }
             bytes.push(b'\n');
 
-            let mut logfile = match logfile {
-                Some(ref l) => l,
-                _ => panic!(),
+            let mut guard = logfile.lock().unwrap();
+            let logfile = match *guard {
+                Some(ref mut l) => l,
+                None => panic!(),
             };
             if let Err(e) = logfile.write_all(&bytes) {
                 eprintln!("Error writing to log file: {}!", e.description());
             }
         }
-    };
+    }
+}
 
-    let exit_code = match relaunch(&loptions, &moptions, logger) {
-        Ok(result) => match result {
-            RelaunchResult::Ok => 0,
-            RelaunchResult::OkAfterRestart(_) => 0,
-            RelaunchResult::RestartCountExceeded(x) => x,
-        },
-        Err(err) => {
-            let msg = match err {
-                RelaunchError::LaunchErr(e) => format!("Error launching target: {}", e.description()),
-                RelaunchError::StderrErr(e) => format!("Error redirecting stderr to file: {}", e.description()),
-                RelaunchError::StdoutErr(e) => format!("Error redirecting stdout to file: {}", e.description()),
+/// Spawns one supervisor thread per configured instance, each running an
+/// independent copy of the `relaunch()` loop with its own fail counter and
+/// backoff state, and waits for all of them to finish. `label`, if given,
+/// prefixes each instance's log tag (used when supervising a named service).
+fn run_instances(loptions: &LaunchOptions, moptions: &MonitorOptions, shutdown: &Arc<AtomicBool>, logfile: &Arc<std::sync::Mutex<Option<std::fs::File>>>, has_log: bool, label: Option<&str>) -> Vec<Result<RelaunchResult, RelaunchError>> {
+    std::thread::scope(|scope| {
+        let handles: Vec<_> = (0..moptions.instances).map(|i| {
+            let instance = i as usize;
+            let shutdown = shutdown.clone();
+            let tag = match label {
+                Some(name) => format!("{} instance {}", name, instance),
+                None => format!("instance {}", instance),
             };
+            let logger = make_logger(logfile, has_log, Some(tag));
+            scope.spawn(move || relaunch(loptions, moptions, logger, &shutdown, instance))
+        }).collect();
 
-            println!("{}", msg);
-            -1
-        }
+        handles.into_iter().map(|h| h.join().unwrap_or_else(|e| Err(RelaunchError::PanicErr(panic_message(&e))))).collect()
+    })
+}
+
+/// Spawns one supervisor thread per service defined in a `--config` file.
+/// A service with `instances > 1` is itself supervised by `run_instances`
+/// on that thread, so each service's slots stay independent of one another.
+/// A service whose `log` key overrides the top-level `--log` path gets its
+/// own log file instead of sharing `logfile`.
+fn run_services(services: Vec<(String, LaunchOptions, MonitorOptions)>, shutdown: &Arc<AtomicBool>, default_log: &Option<PathBuf>, logfile: &Arc<std::sync::Mutex<Option<std::fs::File>>>, has_log: bool) -> Vec<(String, Vec<Result<RelaunchResult, RelaunchError>>)> {
+    std::thread::scope(|scope| {
+        let handles: Vec<_> = services.iter().map(|(name, loptions, moptions)| {
+            let shutdown = shutdown.clone();
+            let (service_logfile, service_has_log) = if moptions.log.is_some() && moptions.log != *default_log {
+                match moptions.log.as_ref().map(|p| open_logfile(p)) {
+                    Some(Ok(f)) => (Arc::new(std::sync::Mutex::new(Some(f))), true),
+                    Some(Err(e)) => {
+                        eprintln!("Could not create log file for service '{}': {}", name, e);
+                        std::process::exit(-1);
+                    },
+                    None => unreachable!(),
+                }
+            }
+            else {
+                (logfile.clone(), has_log)
+            };
+            let thread_name = name.clone();
+            let handle = scope.spawn(move || {
+                let results = if moptions.instances > 1 {
+                    run_instances(loptions, moptions, &shutdown, &service_logfile, service_has_log, Some(name.as_str()))
+                }
+                else {
+                    let logger = make_logger(&service_logfile, service_has_log, Some(name.clone()));
+                    vec![relaunch(loptions, moptions, logger, &shutdown, 0)]
+                };
+                (name.clone(), results)
+            });
+            (thread_name, handle)
+        }).collect();
+
+        handles.into_iter().map(|(name, h)| {
+            h.join().unwrap_or_else(|e| (name, vec![Err(RelaunchError::PanicErr(panic_message(&e)))]))
+        }).collect()
+    })
+}
+
+/// Extracts a human-readable message from a thread panic payload, falling
+/// back to a generic message for payloads that aren't a `&str`/`String`
+/// (the two types `std::panic!`/`.unwrap()` panics carry in practice).
+fn panic_message(payload: &Box<dyn std::any::Any + Send>) -> String {
+    if let Some(s) = payload.downcast_ref::<&str>() {
+        s.to_string()
+    }
+    else if let Some(s) = payload.downcast_ref::<String>() {
+        s.clone()
+    }
+    else {
+        "unknown panic".to_owned()
+    }
+}
+
+/// Reduces the results of a `--config` run into a single process exit code,
+/// logging a per-service summary along the way.
+fn summarize_services<L>(results: &[(String, Vec<Result<RelaunchResult, RelaunchError>>)], mut logger: L) -> i32
+    where L: FnMut(&str)
+{
+    let mut worst_exit_code = 0;
+
+    for (name, instance_results) in results {
+        logger(&format!("Service '{}':", name));
+        let code = summarize_instances(instance_results, |line: &str| logger(&format!("  {}", line)));
+        worst_exit_code = worst_exit_code.max(code);
+    }
+
+    worst_exit_code
+}
+
+/// Parses a `--config` TOML file into one `(name, LaunchOptions,
+/// MonitorOptions)` triple per top-level service table. CLI-supplied
+/// options in `defaults` are the starting point for every service; each
+/// service's table may override any of them.
+fn load_config(path: &PathBuf, defaults: &MonitorOptions) -> Result<Vec<(String, LaunchOptions, MonitorOptions)>, String> {
+    let contents = std::fs::read_to_string(path).map_err(|e| format!("could not read {}: {}", path.display(), e))?;
+    let doc: toml::Value = contents.parse().map_err(|e| format!("invalid TOML: {}", e))?;
+    let table = doc.as_table().ok_or_else(|| "expected a table of services at the top level".to_owned())?;
+
+    let mut services = Vec::new();
+    for (name, value) in table {
+        let service = value.as_table().ok_or_else(|| format!("service '{}' must be a table", name))?;
+        let (loptions, moptions) = parse_service(name, service, defaults)?;
+        services.push((name.clone(), loptions, moptions));
+    }
+
+    if services.is_empty() {
+        return Err("no services defined".to_owned());
+    }
+
+    Ok(services)
+}
+
+fn parse_service(name: &str, table: &toml::value::Table, defaults: &MonitorOptions) -> Result<(LaunchOptions, MonitorOptions), String> {
+    let target = table.get("target").and_then(|v| v.as_str())
+        .ok_or_else(|| format!("service '{}' is missing a 'target' string", name))?;
+
+    let args = match table.get("args") {
+        Some(v) => {
+            let array = v.as_array().ok_or_else(|| format!("service '{}': 'args' must be an array of strings", name))?;
+            array.iter()
+                .map(|a| a.as_str().map(OsString::from).ok_or_else(|| format!("service '{}': 'args' must be an array of strings", name)))
+                .collect::<Result<Vec<_>, _>>()?
+        },
+        None => Vec::new(),
     };
 
-    std::process::exit(exit_code);
+    let loptions = LaunchOptions {
+        exe: OsString::from(target),
+        args,
+    };
+
+    let mut moptions = defaults.clone();
+    moptions.config = None;
+
+    if let Some(v) = table.get("stdout") { moptions.stdout = Some(PathBuf::from(toml_str(v, name, "stdout")?)); }
+    if let Some(v) = table.get("stderr") { moptions.stderr = Some(PathBuf::from(toml_str(v, name, "stderr")?)); }
+    if let Some(v) = table.get("log") { moptions.log = Some(PathBuf::from(toml_str(v, name, "log")?)); }
+    if let Some(v) = table.get("max-restarts") { moptions.max_restarts = Some(toml_int(v, name, "max-restarts")? as i32); }
+    if let Some(v) = table.get("restart-interval") { moptions.restart_interval = Some(toml_int(v, name, "restart-interval")? as i32); }
+    if let Some(v) = table.get("always-restart") { moptions.restart_always = toml_bool(v, name, "always-restart")?; }
+    if let Some(v) = table.get("instances") { moptions.instances = toml_int(v, name, "instances")? as i32; }
+    if let Some(v) = table.get("stop-signal") {
+        moptions.stop_signal = parse_signal(toml_str(v, name, "stop-signal")?)
+            .ok_or_else(|| format!("service '{}': invalid 'stop-signal'", name))?;
+    }
+    if let Some(v) = table.get("stop-timeout") { moptions.stop_timeout = toml_int(v, name, "stop-timeout")? as u64; }
+    if let Some(v) = table.get("backoff-base") { moptions.backoff_base = toml_int(v, name, "backoff-base")? as u64; }
+    if let Some(v) = table.get("backoff-cap") { moptions.backoff_cap = toml_int(v, name, "backoff-cap")? as u64; }
+    if let Some(v) = table.get("jitter") { moptions.jitter = toml_bool(v, name, "jitter")?; }
+    if let Some(v) = table.get("memory-limit") { moptions.memory_limit = Some(toml_int(v, name, "memory-limit")? as u64); }
+    if let Some(v) = table.get("cpu-limit") { moptions.cpu_limit = Some(toml_int(v, name, "cpu-limit")? as u64); }
+    if let Some(v) = table.get("max-fds") { moptions.max_fds = Some(toml_int(v, name, "max-fds")? as u64); }
+    if let Some(v) = table.get("core-limit") { moptions.core_limit = Some(toml_int(v, name, "core-limit")? as u64); }
+    if let Some(v) = table.get("chroot") { moptions.chroot = Some(PathBuf::from(toml_str(v, name, "chroot")?)); }
+    if let Some(v) = table.get("chdir") { moptions.chdir = Some(PathBuf::from(toml_str(v, name, "chdir")?)); }
+    if let Some(v) = table.get("user") {
+        moptions.uid = Some(resolve_user(toml_str(v, name, "user")?).map_err(|e| format!("service '{}': {}", name, e))?);
+    }
+    if let Some(v) = table.get("group") {
+        moptions.gid = Some(resolve_group(toml_str(v, name, "group")?).map_err(|e| format!("service '{}': {}", name, e))?);
+    }
+    if let Some(v) = table.get("restart-codes") {
+        moptions.restart_codes = Some(parse_code_list(toml_str(v, name, "restart-codes")?).map_err(|e| format!("service '{}': {}", name, e))?);
+    }
+    if let Some(v) = table.get("no-restart-codes") {
+        moptions.no_restart_codes = Some(parse_code_list(toml_str(v, name, "no-restart-codes")?).map_err(|e| format!("service '{}': {}", name, e))?);
+    }
+    if let Some(v) = table.get("no-restart-on-signal") { moptions.restart_on_signal = !toml_bool(v, name, "no-restart-on-signal")?; }
+
+    Ok((loptions, moptions))
+}
+
+fn toml_str<'a>(v: &'a toml::Value, service: &str, key: &str) -> Result<&'a str, String> {
+    v.as_str().ok_or_else(|| format!("service '{}': '{}' must be a string", service, key))
+}
+
+fn toml_int(v: &toml::Value, service: &str, key: &str) -> Result<i64, String> {
+    v.as_integer().ok_or_else(|| format!("service '{}': '{}' must be an integer", service, key))
+}
+
+fn toml_bool(v: &toml::Value, service: &str, key: &str) -> Result<bool, String> {
+    v.as_bool().ok_or_else(|| format!("service '{}': '{}' must be a boolean", service, key))
+}
+
+/// Reduces the per-instance results of a multi-instance run into a single
+/// process exit code, logging a one-line summary of each slot's restart
+/// count along the way.
+fn summarize_instances<L>(results: &[Result<RelaunchResult, RelaunchError>], mut logger: L) -> i32
+    where L: FnMut(&str)
+{
+    let mut worst_exit_code = 0;
+
+    for (i, result) in results.iter().enumerate() {
+        match *result {
+            Ok(ref result) => {
+                logger(&format!("Instance {} finished: {}", i, describe_relaunch_result(result)));
+                worst_exit_code = worst_exit_code.max(result_exit_code(result));
+            },
+            Err(ref err) => {
+                logger(&format!("Instance {} failed: {}", i, describe_relaunch_error(err)));
+                worst_exit_code = worst_exit_code.max(1);
+            },
+        }
+    }
+
+    worst_exit_code
+}
+
+fn result_exit_code(result: &RelaunchResult) -> i32 {
+    match *result {
+        RelaunchResult::Ok => 0,
+        RelaunchResult::OkAfterRestart(_) => 0,
+        RelaunchResult::RestartCountExceeded(x) => x,
+        RelaunchResult::ExitCodeExcluded(x) => x,
+        RelaunchResult::Shutdown => 0,
+    }
+}
+
+fn describe_relaunch_result(result: &RelaunchResult) -> String {
+    match *result {
+        RelaunchResult::Ok => "exited cleanly, no restarts".to_owned(),
+        RelaunchResult::OkAfterRestart(x) => format!("exited cleanly after {} restart(s)", x),
+        RelaunchResult::RestartCountExceeded(x) => format!("gave up after {} restart(s)", x),
+        RelaunchResult::ExitCodeExcluded(x) => format!("stopped after {} failed run(s): exit code excluded by restart policy", x),
+        RelaunchResult::Shutdown => "shut down on request".to_owned(),
+    }
+}
+
+fn describe_relaunch_error(err: &RelaunchError) -> String {
+    match *err {
+        RelaunchError::LaunchErr(ref e) => format!("Error launching target: {}", e.description()),
+        RelaunchError::StderrErr(ref e) => format!("Error redirecting stderr to file: {}", e.description()),
+        RelaunchError::StdoutErr(ref e) => format!("Error redirecting stdout to file: {}", e.description()),
+        RelaunchError::PreExecErr(ref e) => format!("Error preparing target for launch (chroot/chdir/privilege-drop/rlimits): {}", e),
+        RelaunchError::PanicErr(ref msg) => format!("Supervisor thread panicked: {}", msg),
+    }
+}
+
+/// Parses a signal given either as a bare number (e.g. "15") or one of the
+/// common POSIX names (e.g. "SIGTERM", case-insensitive, with or without
+/// the "SIG" prefix).
+fn parse_signal(s: &str) -> Option<i32> {
+    if let Ok(n) = s.parse::<i32>() {
+        return Some(n);
+    }
+
+    let normalized = s.to_uppercase();
+    let name = normalized.trim_start_matches("SIG");
+    match name {
+        "HUP" => Some(libc::SIGHUP),
+        "INT" => Some(libc::SIGINT),
+        "QUIT" => Some(libc::SIGQUIT),
+        "KILL" => Some(libc::SIGKILL),
+        "TERM" => Some(libc::SIGTERM),
+        "USR1" => Some(libc::SIGUSR1),
+        "USR2" => Some(libc::SIGUSR2),
+        _ => None,
+    }
+}
+
+/// Resolves a `--user` argument to a numeric uid, accepting either a bare
+/// number or a username to be looked up via `getpwnam`.
+fn resolve_user(s: &str) -> Result<libc::uid_t, String> {
+    if let Ok(uid) = s.parse::<libc::uid_t>() {
+        return Ok(uid);
+    }
+
+    let cname = std::ffi::CString::new(s).map_err(|e| e.to_string())?;
+    let pwd = unsafe { libc::getpwnam(cname.as_ptr()) };
+    if pwd.is_null() {
+        return Err("no such user".to_owned());
+    }
+    Ok(unsafe { (*pwd).pw_uid })
+}
+
+/// Resolves a `--group` argument to a numeric gid, accepting either a bare
+/// number or a group name to be looked up via `getgrnam`.
+fn resolve_group(s: &str) -> Result<libc::gid_t, String> {
+    if let Ok(gid) = s.parse::<libc::gid_t>() {
+        return Ok(gid);
+    }
+
+    let cname = std::ffi::CString::new(s).map_err(|e| e.to_string())?;
+    let grp = unsafe { libc::getgrnam(cname.as_ptr()) };
+    if grp.is_null() {
+        return Err("no such group".to_owned());
+    }
+    Ok(unsafe { (*grp).gr_gid })
+}
+
+/// Parses a comma-separated list of exit codes and inclusive ranges, e.g.
+/// "1,2,69-75", into the flattened list of codes it describes.
+fn parse_code_list(s: &str) -> Result<Vec<i32>, String> {
+    let mut codes = Vec::new();
+
+    for part in s.split(',') {
+        let part = part.trim();
+        if let Some(dash) = part.find('-') {
+            let (lo, hi) = (&part[..dash], &part[dash + 1..]);
+            let lo: i32 = lo.parse().map_err(|_| format!("'{}' is not a valid range", part))?;
+            let hi: i32 = hi.parse().map_err(|_| format!("'{}' is not a valid range", part))?;
+            if lo > hi {
+                return Err(format!("'{}' is not a valid range", part));
+            }
+            codes.extend(lo..=hi);
+        }
+        else {
+            codes.push(part.parse().map_err(|_| format!("'{}' is not a valid exit code", part))?);
+        }
+    }
+
+    Ok(codes)
 }
 
 fn unwrap_argument<T>(matches: &Matches, arg: &'static str, msg: &'static str) -> T
@@ -176,19 +722,29 @@ fn unwrap_argument2<T>(matches: &Matches, arg: &'static str) -> T
     matches.opt_str(arg).unwrap().into()
 }
 
-fn relaunch<L>(loptions: &LaunchOptions, moptions: &MonitorOptions, mut logger: L) -> Result<RelaunchResult, RelaunchError>
-    where L: FnMut(&str) -> ()
+fn relaunch<L>(loptions: &LaunchOptions, moptions: &MonitorOptions, mut logger: L, shutdown: &Arc<AtomicBool>, instance: usize) -> Result<RelaunchResult, RelaunchError>
+    where L: FnMut(&str)
 {
     use std::fs::OpenOptions;
     use std::process::Command;
+    use std::os::unix::process::CommandExt;
+    use std::os::unix::process::ExitStatusExt;
 
     let mut fail_count = 0;
     let mut start_count = 0;
     let mut exit_code = None;
+    let mut consecutive_failures: u32 = 0;
+    let mut excluded_by_policy = false;
 
     loop {
-        let mut cmd = Command::new(loptions.exe);
+        if shutdown.load(Ordering::SeqCst) {
+            logger("Shutdown requested, not relaunching target.");
+            return Ok(RelaunchResult::Shutdown);
+        }
+
+        let mut cmd = Command::new(&loptions.exe);
         cmd.args(&loptions.args);
+        cmd.env("RELAUNCH_INSTANCE", instance.to_string());
 
         if let Some(ref path_stdout) = moptions.stdout {
             let stdout = OpenOptions::new().create(true).append(true).open(path_stdout).map_err(|e| RelaunchError::StdoutErr(e))?;
@@ -199,23 +755,105 @@ fn relaunch<L>(loptions: &LaunchOptions, moptions: &MonitorOptions, mut logger:
             cmd.stderr(stderr);
         }
 
-        let mut child = cmd.spawn().map_err(|e| RelaunchError::LaunchErr(e))?;
-        logger(&format!("Monitoring new child process {} with pid {}", loptions.exe, child.id()));
+        let has_pre_exec = moptions.chroot.is_some() || moptions.chdir.is_some() || moptions.gid.is_some() || moptions.uid.is_some()
+            || moptions.memory_limit.is_some() || moptions.cpu_limit.is_some() || moptions.max_fds.is_some() || moptions.core_limit.is_some();
+
+        // A `pre_exec` closure's error and a genuine `execve()` failure both
+        // cross the fork/exec boundary the same way, so `cmd.spawn()`'s
+        // returned `io::Error` alone can't tell them apart. We hand any
+        // registered pre_exec closure the write end of a pipe and have it
+        // write a single marker byte if it fails; after spawn() we check
+        // for that byte to know whether the failure was ours or the
+        // target's. The closures must stay async-signal-safe (no
+        // allocation) between fork and exec, so this uses a raw fd and a
+        // raw `write(2)`/`read(2)` rather than any higher-level Rust I/O.
+        let mut pre_exec_err_fds = [-1 as libc::c_int; 2];
+        if has_pre_exec && unsafe { libc::pipe2(pre_exec_err_fds.as_mut_ptr(), libc::O_CLOEXEC | libc::O_NONBLOCK) } != 0 {
+            return Err(RelaunchError::LaunchErr(std::io::Error::last_os_error()));
+        }
+        let (pre_exec_err_read, pre_exec_err_write) = (pre_exec_err_fds[0], pre_exec_err_fds[1]);
+
+        if moptions.chroot.is_some() || moptions.chdir.is_some() || moptions.gid.is_some() || moptions.uid.is_some() {
+            let chroot = moptions.chroot.as_deref().map(path_to_cstring).transpose().map_err(RelaunchError::LaunchErr)?;
+            let chdir = moptions.chdir.as_deref().map(path_to_cstring).transpose().map_err(RelaunchError::LaunchErr)?;
+            let gid = moptions.gid;
+            let uid = moptions.uid;
+            unsafe {
+                cmd.pre_exec(move || {
+                    apply_isolation(&chroot, &chdir, gid, uid).inspect_err(|_| mark_pre_exec_failure(pre_exec_err_write))
+                });
+            }
+        }
+
+        if moptions.memory_limit.is_some() || moptions.cpu_limit.is_some() || moptions.max_fds.is_some() || moptions.core_limit.is_some() {
+            let memory_limit = moptions.memory_limit;
+            let cpu_limit = moptions.cpu_limit;
+            let max_fds = moptions.max_fds;
+            let core_limit = moptions.core_limit;
+            unsafe {
+                cmd.pre_exec(move || {
+                    apply_rlimits(memory_limit, cpu_limit, max_fds, core_limit).inspect_err(|_| mark_pre_exec_failure(pre_exec_err_write))
+                });
+            }
+        }
+
+        let spawn_result = cmd.spawn();
+        if has_pre_exec {
+            unsafe { libc::close(pre_exec_err_write) };
+        }
+        let mut child = match spawn_result {
+            Ok(child) => {
+                if has_pre_exec {
+                    unsafe { libc::close(pre_exec_err_read) };
+                }
+                child
+            }
+            Err(e) => {
+                let mut failed_in_pre_exec = false;
+                if has_pre_exec {
+                    let mut marker = [0u8; 1];
+                    loop {
+                        let n = unsafe { libc::read(pre_exec_err_read, marker.as_mut_ptr() as *mut libc::c_void, 1) };
+                        if n == -1 && std::io::Error::last_os_error().kind() == std::io::ErrorKind::Interrupted {
+                            continue;
+                        }
+                        failed_in_pre_exec = n == 1;
+                        break;
+                    }
+                    unsafe { libc::close(pre_exec_err_read) };
+                }
+                return Err(if failed_in_pre_exec { RelaunchError::PreExecErr(e) } else { RelaunchError::LaunchErr(e) });
+            }
+        };
+        logger(&format!("Monitoring new child process {} with pid {}", loptions.exe.to_string_lossy(), child.id()));
 
         start_count += 1;
-        let status = child.wait().unwrap();
+        let spawned_at = std::time::Instant::now();
+        let status = wait_for_child(&mut child, moptions, shutdown, &mut logger);
+
+        if shutdown.load(Ordering::SeqCst) {
+            logger(&format!("Child process {} terminated for shutdown.", loptions.exe.to_string_lossy()));
+            return Ok(RelaunchResult::Shutdown);
+        }
+
+        let status = status.unwrap();
+        let ran_for = spawned_at.elapsed();
 
         if !status.success() {
-            logger(&format!("Child process {} exited {}", loptions.exe, match status.code() {
+            logger(&format!("Child process {} exited {}", loptions.exe.to_string_lossy(), match status.code() {
                 Some(x) => format!("exit code {}", x),
-                None => "due to signal!".to_owned(),
+                None => match status.signal() {
+                    Some(libc::SIGXCPU) => "after exceeding its CPU time limit (SIGXCPU)!".to_owned(),
+                    Some(libc::SIGKILL) if moptions.memory_limit.is_some() => "due to signal (possibly OOM-killed after exceeding its memory limit)!".to_owned(),
+                    _ => "due to signal!".to_owned(),
+                },
             }));
             fail_count += 1;
         }
         if status.success() {
-            logger(&format!("Child process {} exited normally.", loptions.exe));
+            logger(&format!("Child process {} exited normally.", loptions.exe.to_string_lossy()));
             if !moptions.restart_always {
-                logger(&format!("Monitoring of process {} complete, exiting.", loptions.exe));
+                logger(&format!("Monitoring of process {} complete, exiting.", loptions.exe.to_string_lossy()));
                 break;
             }
         }
@@ -223,15 +861,47 @@ fn relaunch<L>(loptions: &LaunchOptions, moptions: &MonitorOptions, mut logger:
         //unix processes exited by a signal return no status code
         exit_code = status.code();
 
+        if !code_allows_restart(exit_code, &moptions.restart_codes, &moptions.no_restart_codes, moptions.restart_on_signal) {
+            logger(&format!("Exit status of process {} is not configured to trigger a restart, terminating relaunch.", loptions.exe.to_string_lossy()));
+            excluded_by_policy = true;
+            break;
+        }
+
+        let ran_long_enough = match moptions.restart_interval {
+            Some(secs) => ran_for >= Duration::from_secs(secs as u64),
+            None => false,
+        };
+        if ran_long_enough {
+            logger(&format!("Child process {} ran for at least {}s, resetting restart counters.", loptions.exe.to_string_lossy(), moptions.restart_interval.unwrap()));
+            fail_count = 0;
+            start_count = 0;
+            consecutive_failures = 0;
+        }
+
         let restart = match moptions.max_restarts {
             None => true,
             Some(x) => x > start_count - 1,
         };
 
         if !restart {
-            logger(&format!("Max restart count exceeded, terminating relaunch of process {}", loptions.exe));
+            logger(&format!("Max restart count exceeded, terminating relaunch of process {}", loptions.exe.to_string_lossy()));
             break;
         }
+
+        if !ran_long_enough {
+            consecutive_failures += 1;
+            let delay = backoff_delay(consecutive_failures, moptions.backoff_base, moptions.backoff_cap, moptions.jitter);
+            let reason = if status.success() { "exited" } else { "crashed" };
+            logger(&format!("Child process {} {} after only {:.1}s, backing off for {:.1}s before relaunching.", loptions.exe.to_string_lossy(), reason, ran_for.as_secs_f64(), delay.as_secs_f64()));
+            if interruptible_sleep(delay, shutdown) {
+                logger("Shutdown requested during backoff, not relaunching target.");
+                return Ok(RelaunchResult::Shutdown);
+            }
+        }
+    }
+
+    if excluded_by_policy {
+        return Ok(RelaunchResult::ExitCodeExcluded(fail_count));
     }
 
     match moptions.restart_always {
@@ -246,35 +916,242 @@ fn relaunch<L>(loptions: &LaunchOptions, moptions: &MonitorOptions, mut logger:
     }
 }
 
-#[derive(Debug)]
-struct LaunchOptions<'a> {
-    exe: &'a str,
-    args: Vec<String>,
+/// Decides whether a restart should be attempted for the given exit code,
+/// consulting the configured allow-list/deny-list. A signal-terminated exit
+/// (`code` is `None`) is instead governed by `restart_on_signal`. The
+/// allow-list takes precedence over the deny-list when both are set.
+fn code_allows_restart(code: Option<i32>, restart_codes: &Option<Vec<i32>>, no_restart_codes: &Option<Vec<i32>>, restart_on_signal: bool) -> bool {
+    let code = match code {
+        Some(code) => code,
+        None => return restart_on_signal,
+    };
+
+    if let Some(ref allow) = *restart_codes {
+        return allow.contains(&code);
+    }
+    if let Some(ref deny) = *no_restart_codes {
+        return !deny.contains(&code);
+    }
+    true
+}
+
+/// Computes how long to wait before relaunching a target that crashed
+/// faster than `restart_interval`. Grows exponentially with the number of
+/// consecutive fast failures, capped at `cap` seconds, with optional
+/// ±10% jitter to avoid several supervised instances retrying in lockstep.
+fn backoff_delay(consecutive_failures: u32, base: u64, cap: u64, jitter: bool) -> Duration {
+    let exponent = consecutive_failures.saturating_sub(1).min(31);
+    let secs = base.saturating_mul(1u64 << exponent).min(cap);
+    let delay = Duration::from_secs(secs);
+
+    if !jitter {
+        return delay;
+    }
+
+    let nanos = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().subsec_nanos();
+    let factor = 0.9 + (nanos as f64 / u32::MAX as f64) * 0.2;
+    Duration::from_secs_f64(delay.as_secs_f64() * factor)
+}
+
+/// Converts a `chroot`/`chdir` path to a NUL-terminated `CString` in the
+/// parent, before `fork()`. `apply_isolation` runs inside a `Command::pre_exec`
+/// closure (after `fork()`, before `exec()`), where the process may have
+/// inherited another thread's held malloc-arena lock from the moment of
+/// fork; allocating there (as `CString::new`/`std::env::set_current_dir`
+/// would) can deadlock. Pre-converting here keeps the closure itself
+/// allocation-free.
+fn path_to_cstring(path: &Path) -> std::io::Result<std::ffi::CString> {
+    std::ffi::CString::new(path.to_string_lossy().as_bytes()).map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))
+}
+
+/// Chroots and drops privileges for the calling process, in the order
+/// `chroot`, `chdir("/")`, `chdir`, clear supplementary groups, drop group,
+/// drop user. Intended to run inside a `Command::pre_exec` closure, after
+/// `fork()` but before `exec()`, so that `relaunch` itself (typically run as
+/// root to set this up) keeps its own privileges while the target does not.
+///
+/// `chroot`/`chdir` are pre-converted `CString`s (see `path_to_cstring`)
+/// rather than `PathBuf`s: this function must stay async-signal-safe, using
+/// only raw syscalls and no allocation, since a `fork()` can land it in a
+/// process that inherited another thread's held malloc-arena lock.
+///
+/// The `chdir("/")` after a successful `chroot()` is mandatory, not
+/// optional: without it the process keeps whatever cwd it had before the
+/// chroot, and a relative path (or a retained fd) can walk back out of the
+/// jail. Likewise, `setgroups(0, NULL)` is called before dropping the gid/uid
+/// so the target doesn't keep the parent's supplementary groups, which would
+/// otherwise defeat the privilege drop.
+fn apply_isolation(chroot: &Option<std::ffi::CString>, chdir: &Option<std::ffi::CString>, gid: Option<libc::gid_t>, uid: Option<libc::uid_t>) -> std::io::Result<()> {
+    if let Some(ref cpath) = *chroot {
+        if unsafe { libc::chroot(cpath.as_ptr()) } != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        if unsafe { libc::chdir(b"/\0".as_ptr() as *const libc::c_char) } != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+    }
+    if let Some(ref cpath) = *chdir {
+        if unsafe { libc::chdir(cpath.as_ptr()) } != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+    }
+    if (gid.is_some() || uid.is_some()) && unsafe { libc::setgroups(0, std::ptr::null()) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    if let Some(gid) = gid {
+        if unsafe { libc::setgid(gid) } != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+    }
+    if let Some(uid) = uid {
+        if unsafe { libc::setuid(uid) } != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+    }
+    Ok(())
 }
 
-#[derive(Debug)]
+/// Applies the configured `setrlimit` resource limits to the calling
+/// process. Intended to run inside a `Command::pre_exec` closure, i.e.
+/// after `fork()` but before `exec()`, so the limits bind the target and
+/// not `relaunch` itself.
+fn apply_rlimits(memory_limit: Option<u64>, cpu_limit: Option<u64>, max_fds: Option<u64>, core_limit: Option<u64>) -> std::io::Result<()> {
+    if let Some(bytes) = memory_limit {
+        set_rlimit(libc::RLIMIT_AS, bytes)?;
+    }
+    if let Some(secs) = cpu_limit {
+        set_rlimit(libc::RLIMIT_CPU, secs)?;
+    }
+    if let Some(n) = max_fds {
+        set_rlimit(libc::RLIMIT_NOFILE, n)?;
+    }
+    if let Some(bytes) = core_limit {
+        set_rlimit(libc::RLIMIT_CORE, bytes)?;
+    }
+    Ok(())
+}
+
+/// Writes a single marker byte to `fd`, the write end of a pipe set up by
+/// `relaunch()`, to flag that a `pre_exec` step failed (as opposed to the
+/// target's own `execve()`). Must stay async-signal-safe: this runs between
+/// `fork()` and `exec()`, so it's a raw `write(2)` with no allocation; a
+/// failed write is ignored; there's nothing safer left to do about it here.
+fn mark_pre_exec_failure(fd: libc::c_int) {
+    let marker: u8 = 1;
+    unsafe {
+        libc::write(fd, &marker as *const u8 as *const libc::c_void, 1);
+    }
+}
+
+fn set_rlimit(resource: libc::__rlimit_resource_t, value: u64) -> std::io::Result<()> {
+    let limit = libc::rlimit {
+        rlim_cur: value as libc::rlim_t,
+        rlim_max: value as libc::rlim_t,
+    };
+    if unsafe { libc::setrlimit(resource, &limit) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Waits for `child` to exit, polling so that a shutdown request can be
+/// noticed mid-wait. Once `shutdown` is set, forwards `moptions.stop_signal`
+/// to the child and gives it up to `moptions.stop_timeout` seconds to exit
+/// before escalating to `SIGKILL`.
+fn wait_for_child<L>(child: &mut std::process::Child, moptions: &MonitorOptions, shutdown: &Arc<AtomicBool>, logger: &mut L) -> std::io::Result<std::process::ExitStatus>
+    where L: FnMut(&str)
+{
+    let mut signalled = false;
+    let mut grace_start = None;
+
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(status);
+        }
+
+        if shutdown.load(Ordering::SeqCst) {
+            if !signalled {
+                logger(&format!("Forwarding signal {} to pid {}", moptions.stop_signal, child.id()));
+                unsafe { libc::kill(child.id() as libc::pid_t, moptions.stop_signal); }
+                signalled = true;
+                grace_start = Some(std::time::Instant::now());
+            }
+            else if grace_start.unwrap().elapsed() >= Duration::from_secs(moptions.stop_timeout) {
+                logger(&format!("pid {} did not exit within {}s, sending SIGKILL", child.id(), moptions.stop_timeout));
+                unsafe { libc::kill(child.id() as libc::pid_t, libc::SIGKILL); }
+                return child.wait();
+            }
+        }
+
+        std::thread::sleep(POLL_INTERVAL);
+    }
+}
+
+#[derive(Debug, Clone)]
+struct LaunchOptions {
+    exe: OsString,
+    args: Vec<OsString>,
+}
+
+#[derive(Debug, Clone)]
 struct MonitorOptions {
-    // instances: i32,
+    instances: i32,
     max_restarts: Option<i32>,
     restart_always: bool,
     restart_interval: Option<i32>,
-    // restart_codes: Option<Vec<i32>>,
+    // stdout/stderr/log/chroot/chdir stay `PathBuf`, sourced from getopts'
+    // `String` values: they go through relaunch's own option parsing, which
+    // requires valid UTF-8 (see the scope note in `main()`). Only TARGET
+    // and passthru args (after `--`) accept non-UTF-8 values.
     stdout: Option<PathBuf>,
     stderr: Option<PathBuf>,
     log: Option<PathBuf>,
+    stop_signal: i32,
+    stop_timeout: u64,
+    backoff_base: u64,
+    backoff_cap: u64,
+    jitter: bool,
+    memory_limit: Option<u64>,
+    cpu_limit: Option<u64>,
+    max_fds: Option<u64>,
+    core_limit: Option<u64>,
+    chroot: Option<PathBuf>,
+    chdir: Option<PathBuf>,
+    uid: Option<libc::uid_t>,
+    gid: Option<libc::gid_t>,
+    restart_codes: Option<Vec<i32>>,
+    no_restart_codes: Option<Vec<i32>>,
+    restart_on_signal: bool,
+    config: Option<PathBuf>,
 }
 
 impl MonitorOptions {
     fn new() -> Self {
         MonitorOptions {
-            // instances: 1,
+            instances: 1,
             max_restarts: Option::None,
             restart_always: false,
             restart_interval: Option::None,
-            // restart_codes: Option::None,
             stdout: Option::None,
             stderr: Option::None,
             log: Option::None,
+            stop_signal: libc::SIGTERM,
+            stop_timeout: 10,
+            backoff_base: 1,
+            backoff_cap: 60,
+            jitter: false,
+            memory_limit: Option::None,
+            cpu_limit: Option::None,
+            max_fds: Option::None,
+            core_limit: Option::None,
+            chroot: Option::None,
+            chdir: Option::None,
+            uid: Option::None,
+            gid: Option::None,
+            restart_codes: Option::None,
+            no_restart_codes: Option::None,
+            restart_on_signal: true,
+            config: Option::None,
         }
     }
 }
@@ -283,10 +1160,184 @@ enum RelaunchResult {
     Ok, //never restarted, clean exit
     OkAfterRestart(i32), //restarted x times with clean exit
     RestartCountExceeded(i32), //attempts
+    ExitCodeExcluded(i32), //stopped because the exit code is excluded by --restart-codes/--no-restart-codes, not because restarts were exhausted
+    Shutdown, //stopped cleanly due to a forwarded signal
 }
 
 enum RelaunchError {
     LaunchErr(std::io::Error),
     StdoutErr(std::io::Error),
     StderrErr(std::io::Error),
+    PreExecErr(std::io::Error),
+    PanicErr(String),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_signal_accepts_bare_numbers() {
+        assert_eq!(parse_signal("15"), Some(15));
+        assert_eq!(parse_signal("9"), Some(9));
+    }
+
+    #[test]
+    fn parse_signal_accepts_names_with_or_without_sig_prefix_case_insensitive() {
+        assert_eq!(parse_signal("SIGTERM"), Some(libc::SIGTERM));
+        assert_eq!(parse_signal("term"), Some(libc::SIGTERM));
+        assert_eq!(parse_signal("sigKILL"), Some(libc::SIGKILL));
+    }
+
+    #[test]
+    fn parse_signal_rejects_unknown_names() {
+        assert_eq!(parse_signal("NOTASIGNAL"), None);
+    }
+
+    #[test]
+    fn backoff_delay_doubles_per_consecutive_failure() {
+        assert_eq!(backoff_delay(1, 1, 60, false), Duration::from_secs(1));
+        assert_eq!(backoff_delay(2, 1, 60, false), Duration::from_secs(2));
+        assert_eq!(backoff_delay(3, 1, 60, false), Duration::from_secs(4));
+    }
+
+    #[test]
+    fn backoff_delay_is_capped() {
+        assert_eq!(backoff_delay(10, 1, 60, false), Duration::from_secs(60));
+    }
+
+    #[test]
+    fn backoff_delay_jitter_stays_within_plus_minus_10_percent() {
+        let base = backoff_delay(3, 1, 60, false).as_secs_f64();
+        let jittered = backoff_delay(3, 1, 60, true).as_secs_f64();
+        assert!(jittered >= base * 0.9 - 1e-9);
+        assert!(jittered <= base * 1.1 + 1e-9);
+    }
+
+    #[test]
+    fn parse_code_list_accepts_commas_and_ranges() {
+        assert_eq!(parse_code_list("1,2,69-71").unwrap(), vec![1, 2, 69, 70, 71]);
+    }
+
+    #[test]
+    fn parse_code_list_rejects_backwards_range() {
+        assert!(parse_code_list("5-1").is_err());
+    }
+
+    #[test]
+    fn parse_code_list_rejects_garbage() {
+        assert!(parse_code_list("nope").is_err());
+    }
+
+    #[test]
+    fn code_allows_restart_allow_list_takes_precedence_over_deny_list() {
+        let allow = Some(vec![1, 2]);
+        let deny = Some(vec![2]);
+        assert!(code_allows_restart(Some(2), &allow, &deny, true));
+        assert!(!code_allows_restart(Some(3), &allow, &deny, true));
+    }
+
+    #[test]
+    fn code_allows_restart_deny_list_excludes_only_its_codes() {
+        let deny = Some(vec![1, 2]);
+        assert!(!code_allows_restart(Some(1), &None, &deny, true));
+        assert!(code_allows_restart(Some(3), &None, &deny, true));
+    }
+
+    #[test]
+    fn code_allows_restart_defers_to_restart_on_signal_when_code_is_none() {
+        assert!(code_allows_restart(None, &None, &None, true));
+        assert!(!code_allows_restart(None, &None, &None, false));
+    }
+
+    fn toml_table(s: &str) -> toml::value::Table {
+        s.parse::<toml::Value>().unwrap().as_table().unwrap().clone()
+    }
+
+    #[test]
+    fn parse_service_reads_target_args_and_overrides() {
+        let table = toml_table(r#"
+            target = "/usr/bin/mysvc"
+            args = ["--flag", "value"]
+            max-restarts = 5
+            always-restart = true
+        "#);
+        let defaults = MonitorOptions::new();
+        let (loptions, moptions) = parse_service("mysvc", &table, &defaults).unwrap();
+        assert_eq!(loptions.exe, OsString::from("/usr/bin/mysvc"));
+        assert_eq!(loptions.args, vec![OsString::from("--flag"), OsString::from("value")]);
+        assert_eq!(moptions.max_restarts, Some(5));
+        assert!(moptions.restart_always);
+    }
+
+    #[test]
+    fn parse_service_requires_target() {
+        let table = toml_table(r#"args = ["x"]"#);
+        assert!(parse_service("mysvc", &table, &MonitorOptions::new()).is_err());
+    }
+
+    #[cfg(unix)]
+    fn non_utf8_os_string() -> OsString {
+        use std::os::unix::ffi::OsStrExt;
+        OsStr::from_bytes(&[0xff, 0xfe]).to_os_string()
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn split_args_recovers_non_utf8_target() {
+        let (args, raw_args, non_utf8_indices, passthru_args) = split_args(vec![non_utf8_os_string()].into_iter());
+        assert_eq!(non_utf8_indices.len(), 1);
+        assert!(passthru_args.is_empty());
+
+        let opts = Options::new();
+        let matches = opts.parse(&args).unwrap();
+        let target_idx = recover_target_idx(&matches.free, &non_utf8_indices).unwrap();
+        assert_eq!(raw_args[target_idx.unwrap()], non_utf8_os_string());
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn split_args_rejects_non_utf8_option_value() {
+        let (args, _raw_args, non_utf8_indices, _passthru_args) = split_args(
+            vec![OsString::from("-o"), non_utf8_os_string(), OsString::from("target")].into_iter()
+        );
+
+        let mut opts = Options::new();
+        opts.optopt("o", "stdout", "", "PATH");
+        let matches = opts.parse(&args).unwrap();
+        assert!(recover_target_idx(&matches.free, &non_utf8_indices).is_err());
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn split_args_rejects_non_utf8_free_arg_that_isnt_the_first_one() {
+        // Two free args is itself an error the caller checks separately, but
+        // a non-UTF-8 index that isn't `free[0]` must still be rejected here.
+        let (args, _raw_args, non_utf8_indices, _passthru_args) = split_args(
+            vec![OsString::from("target"), non_utf8_os_string()].into_iter()
+        );
+
+        let opts = Options::new();
+        let matches = opts.parse(&args).unwrap();
+        assert_eq!(matches.free.len(), 2);
+        assert!(recover_target_idx(&matches.free, &non_utf8_indices).is_err());
+    }
+
+    #[test]
+    fn parse_service_rejects_non_string_args() {
+        let table = toml_table(r#"
+            target = "/usr/bin/mysvc"
+            args = [1, 2]
+        "#);
+        assert!(parse_service("mysvc", &table, &MonitorOptions::new()).is_err());
+    }
+
+    #[test]
+    fn parse_service_rejects_wrong_value_type() {
+        let table = toml_table(r#"
+            target = "/usr/bin/mysvc"
+            max-restarts = "five"
+        "#);
+        assert!(parse_service("mysvc", &table, &MonitorOptions::new()).is_err());
+    }
 }